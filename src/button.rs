@@ -0,0 +1,130 @@
+//! Debounced button input with short/long/double-press gesture detection.
+//!
+//! Replaces the four copy-pasted `if btnN.is_low() && ...` blocks with a
+//! `Button` struct that's ticked once per main-loop iteration and a `Vec`
+//! the loop can simply iterate over.
+
+use anyhow::Result;
+use esp_idf_hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+
+/// Consecutive stable ticks required before a raw level change is trusted.
+const DEBOUNCE_TICKS: u32 = 3;
+/// Minimum hold duration for a press to count as "long".
+const LONG_PRESS_MS: u32 = 1000;
+/// Maximum gap between two short presses for them to count as "double".
+const DOUBLE_PRESS_WINDOW_MS: u32 = 400;
+
+/// A gesture recognized from a button's press pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// A single press shorter than [`LONG_PRESS_MS`].
+    Short,
+    /// A press held for at least [`LONG_PRESS_MS`].
+    Long,
+    /// Two short presses within [`DOUBLE_PRESS_WINDOW_MS`] of each other.
+    Double,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    Idle,
+    Pressed { held_ms: u32 },
+    LongFired,
+    WaitingSecondPress { waited_ms: u32 },
+}
+
+/// A single debounced button, mapped to the relay it controls on a short
+/// press.
+pub struct Button {
+    pub relay_id: usize,
+    pin: PinDriver<'static, AnyIOPin, Input>,
+    pending_low: bool,
+    debounced_low: bool,
+    debounce_ticks_elapsed: u32,
+    state: State,
+}
+
+impl Button {
+    pub fn new(pin: AnyIOPin, relay_id: usize) -> Result<Self> {
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        pin.set_interrupt_type(InterruptType::LowLevel)?;
+        Ok(Self {
+            relay_id,
+            pin,
+            pending_low: false,
+            debounced_low: false,
+            debounce_ticks_elapsed: 0,
+            state: State::Idle,
+        })
+    }
+
+    /// Advances debounce and press-timing state by one main-loop tick.
+    /// Returns a gesture if one was just completed.
+    pub fn tick(&mut self, tick_ms: u32) -> Option<Gesture> {
+        let raw_low = self.pin.is_low();
+        if raw_low == self.pending_low {
+            self.debounce_ticks_elapsed += 1;
+        } else {
+            self.pending_low = raw_low;
+            self.debounce_ticks_elapsed = 0;
+        }
+
+        let mut gesture = None;
+        if self.debounce_ticks_elapsed >= DEBOUNCE_TICKS && self.debounced_low != self.pending_low {
+            self.debounced_low = self.pending_low;
+            gesture = if self.debounced_low {
+                self.on_press()
+            } else {
+                self.on_release()
+            };
+        }
+
+        match &mut self.state {
+            State::Pressed { held_ms } => {
+                *held_ms += tick_ms;
+                if *held_ms >= LONG_PRESS_MS {
+                    self.state = State::LongFired;
+                    gesture = Some(Gesture::Long);
+                }
+            }
+            State::WaitingSecondPress { waited_ms } => {
+                *waited_ms += tick_ms;
+                if *waited_ms >= DOUBLE_PRESS_WINDOW_MS {
+                    self.state = State::Idle;
+                    gesture = Some(Gesture::Short);
+                }
+            }
+            State::Idle | State::LongFired => {}
+        }
+
+        gesture
+    }
+
+    fn on_press(&mut self) -> Option<Gesture> {
+        match self.state {
+            State::WaitingSecondPress { .. } => {
+                self.state = State::Idle;
+                Some(Gesture::Double)
+            }
+            _ => {
+                self.state = State::Pressed { held_ms: 0 };
+                None
+            }
+        }
+    }
+
+    fn on_release(&mut self) -> Option<Gesture> {
+        match self.state {
+            State::Pressed { .. } => {
+                self.state = State::WaitingSecondPress { waited_ms: 0 };
+                None
+            }
+            State::LongFired => {
+                self.state = State::Idle;
+                None
+            }
+            State::Idle | State::WaitingSecondPress { .. } => None,
+        }
+    }
+}
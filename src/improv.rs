@@ -0,0 +1,161 @@
+//! Improv Wi-Fi serial provisioning.
+//!
+//! Implements the subset of the Improv protocol
+//! (<https://www.improv-wifi.com/serial/>) needed to accept a "set WiFi"
+//! command over the USB serial console, so credentials can be changed at
+//! runtime instead of requiring a reflash.
+//!
+//! Packet framing: `IMPROV` + version (`0x01`) + type byte + length byte +
+//! payload + checksum byte (sum of everything before it, mod 256).
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+
+use crate::storage::WifiCredentials;
+
+const HEADER: &[u8; 6] = b"IMPROV";
+const VERSION: u8 = 0x01;
+
+const TYPE_CURRENT_STATE: u8 = 0x01;
+const TYPE_ERROR_STATE: u8 = 0x02;
+const TYPE_RPC_COMMAND: u8 = 0x03;
+const TYPE_RPC_RESULT: u8 = 0x04;
+
+const STATE_AUTHORIZED: u8 = 0x02;
+const STATE_PROVISIONING: u8 = 0x03;
+const STATE_PROVISIONED: u8 = 0x04;
+
+const ERROR_UNABLE_TO_CONNECT: u8 = 0x03;
+
+const COMMAND_SET_WIFI: u8 = 0x01;
+
+/// Blocks on the serial console, handling Improv packets, until WiFi
+/// credentials are accepted and `try_connect` succeeds with them.
+///
+/// `try_connect` is given the SSID and password from the `set WiFi` command
+/// and must return the lamp's HTTP URL on success, which is reported back to
+/// the Improv client.
+pub fn provision(mut try_connect: impl FnMut(&str, &str) -> Result<String>) -> Result<WifiCredentials> {
+    let mut stdin = std::io::stdin();
+
+    send_state(STATE_AUTHORIZED)?;
+    log::info!("Improv: waiting for WiFi credentials over serial");
+
+    loop {
+        let Some((packet_type, payload)) = read_packet(&mut stdin)? else {
+            continue;
+        };
+        if packet_type != TYPE_RPC_COMMAND {
+            continue;
+        }
+        let Some((COMMAND_SET_WIFI, ssid, password)) = parse_set_wifi(&payload) else {
+            continue;
+        };
+
+        send_state(STATE_PROVISIONING)?;
+        match try_connect(&ssid, &password) {
+            Ok(url) => {
+                send_state(STATE_PROVISIONED)?;
+                send_rpc_result(COMMAND_SET_WIFI, &url)?;
+                return Ok(WifiCredentials { ssid, password });
+            }
+            Err(e) => {
+                log::warn!("Improv: failed to connect to {ssid}: {e}");
+                send_error(ERROR_UNABLE_TO_CONNECT)?;
+                send_state(STATE_AUTHORIZED)?;
+            }
+        }
+    }
+}
+
+/// Reads one Improv packet, resyncing on the `IMPROV` header. Returns
+/// `Ok(None)` if the checksum didn't match so the caller can just try again.
+fn read_packet(stdin: &mut impl Read) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut window = [0_u8; 6];
+    stdin.read_exact(&mut window)?;
+    while window != *HEADER {
+        window.copy_within(1.., 0);
+        stdin.read_exact(&mut window[5..6])?;
+    }
+
+    let mut head = [0_u8; 3];
+    stdin.read_exact(&mut head)?;
+    let [version, packet_type, len] = head;
+    if version != VERSION {
+        bail!("unsupported Improv version {version}");
+    }
+
+    let mut payload = vec![0_u8; len as usize];
+    stdin.read_exact(&mut payload)?;
+
+    let mut checksum_buf = [0_u8; 1];
+    stdin.read_exact(&mut checksum_buf)?;
+
+    let computed = HEADER
+        .iter()
+        .chain(&head)
+        .chain(payload.iter())
+        .fold(0_u8, |sum, b| sum.wrapping_add(*b));
+    if computed != checksum_buf[0] {
+        log::warn!("Improv: checksum mismatch, dropping packet");
+        return Ok(None);
+    }
+
+    Ok(Some((packet_type, payload)))
+}
+
+/// Parses an RPC command payload: command id, data length, then within that
+/// data a length-prefixed SSID and a length-prefixed password (the "set
+/// WiFi" command shape).
+fn parse_set_wifi(payload: &[u8]) -> Option<(u8, String, String)> {
+    let (&command_id, rest) = payload.split_first()?;
+    let (&data_len, rest) = rest.split_first()?;
+    let data = rest.get(..data_len as usize)?;
+    let (ssid, rest) = read_length_prefixed(data)?;
+    let (password, _rest) = read_length_prefixed(rest)?;
+    Some((command_id, ssid, password))
+}
+
+fn read_length_prefixed(data: &[u8]) -> Option<(String, &[u8])> {
+    let (&len, rest) = data.split_first()?;
+    let len = len as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let s = std::str::from_utf8(&rest[..len]).ok()?.to_string();
+    Some((s, &rest[len..]))
+}
+
+fn send_state(state: u8) -> Result<()> {
+    send_packet(TYPE_CURRENT_STATE, &[state])
+}
+
+fn send_error(error: u8) -> Result<()> {
+    send_packet(TYPE_ERROR_STATE, &[error])
+}
+
+fn send_rpc_result(command_id: u8, url: &str) -> Result<()> {
+    // Data section is `[command, data_length, <length-prefixed strings>]`;
+    // here the only string is the URL, so data_length is its length plus
+    // the one byte needed to length-prefix it.
+    let mut payload = vec![command_id, url.len() as u8 + 1, url.len() as u8];
+    payload.extend_from_slice(url.as_bytes());
+    send_packet(TYPE_RPC_RESULT, &payload)
+}
+
+fn send_packet(packet_type: u8, payload: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(HEADER.len() + 3 + payload.len() + 1);
+    frame.extend_from_slice(HEADER);
+    frame.push(VERSION);
+    frame.push(packet_type);
+    frame.push(payload.len() as u8);
+    frame.extend_from_slice(payload);
+    let checksum = frame.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b));
+    frame.push(checksum);
+
+    let mut stdout = std::io::stdout();
+    stdout.write_all(&frame)?;
+    stdout.flush()?;
+    Ok(())
+}
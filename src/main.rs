@@ -1,25 +1,44 @@
+mod button;
+mod improv;
+mod mqtt;
+mod relay;
+mod schedule;
+mod storage;
+
 use anyhow::{Error, Result};
+use button::{Button, Gesture};
 use esp_idf_hal::{
     delay::FreeRtos,
-    gpio::{AnyIOPin, InterruptType, PinDriver, Pull},
-    io::Write,
+    gpio::{AnyIOPin, PinDriver},
+    io::{Read, Write},
     peripherals::Peripherals,
 };
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     http::server::EspHttpServer,
     nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
     wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
 };
-use serde_json::json;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
-};
+use mqtt::Mqtt;
+use relay::{Relay, RelayMode, RelaySet};
+use schedule::{ScheduleEntry, Scheduler};
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use storage::{BootPolicy, RelayStore, ScheduleStore, WifiStore};
 use url::Url;
 
-const SSID: &str = "iQOO Z7x 5G";
-const PASSWORD: &str = "123456789";
+const MQTT_BROKER_URL: &str = "mqtt://192.168.1.1:1883";
+/// How long the relay-state bitmask must stay unchanged before it's
+/// committed to NVS, in main-loop ticks (the loop sleeps 10 ms per tick).
+const RELAY_MASK_DEBOUNCE_TICKS: u32 = 30;
+/// Main-loop tick period in ms; drives both button polling and momentary
+/// pulse bookkeeping.
+const LOOP_TICK_MS: u32 = 10;
+
+/// Shared relay handles, keyed by their index into the set (their `id` in
+/// the JSON/HTTP API).
+pub type Relays = Arc<Mutex<RelaySet>>;
 
 fn main() -> Result<()> {
     esp_idf_svc::sys::link_patches();
@@ -36,57 +55,92 @@ fn main() -> Result<()> {
 
     log::debug!("Initializing WiFi");
     let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs))?,
+        EspWifi::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone()))?,
         sys_loop,
     )?;
 
-    connect_wifi(&mut wifi)?;
+    let mut wifi_store = WifiStore::new(nvs.clone())?;
+    let stored_creds = wifi_store.load();
+    let connected = stored_creds
+        .as_ref()
+        .is_some_and(|creds| connect_wifi(&mut wifi, &creds.ssid, &creds.password).is_ok());
+
+    let wifi_creds = if connected {
+        stored_creds.unwrap()
+    } else {
+        log::info!("No usable WiFi credentials stored; starting Improv provisioning");
+        let creds = improv::provision(|ssid, password| {
+            connect_wifi(&mut wifi, ssid, password)?;
+            let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+            Ok(format!("http://{}:8080/", ip_info.ip))
+        })?;
+        wifi_store.save(&creds)?;
+        creds
+    };
+
+    log::debug!("Initializing relay storage");
+    let mut relay_store = RelayStore::new(nvs.clone())?;
+    let boot_policy = relay_store.load_boot_policy();
+    let saved_mask = relay_store.load_mask();
 
     log::debug!("Initializing relays");
     let relays = {
         let mut relays = Vec::new();
         let pin: AnyIOPin = peripherals.pins.gpio13.into();
-        relays.push(("lampu kamar", PinDriver::input_output(pin)?));
+        relays.push(Relay::new(0, "lampu kamar", PinDriver::input_output(pin)?));
         let pin: AnyIOPin = peripherals.pins.gpio12.into();
-        relays.push(("lampu keluarga", PinDriver::input_output(pin)?));
+        relays.push(Relay::new(1, "lampu keluarga", PinDriver::input_output(pin)?));
         let pin: AnyIOPin = peripherals.pins.gpio14.into();
-        relays.push(("lampu ruang tamu", PinDriver::input_output(pin)?));
+        relays.push(Relay::new(2, "lampu ruang tamu", PinDriver::input_output(pin)?));
         let pin: AnyIOPin = peripherals.pins.gpio27.into();
-        relays.push(("lampu dapur", PinDriver::input_output(pin)?));
-        Arc::new(Mutex::new(relays))
+        relays.push(Relay::new(3, "lampu dapur", PinDriver::input_output(pin)?));
+
+        let boot_mask = storage::initial_mask(boot_policy, saved_mask, relays.len());
+        for relay in relays.iter_mut() {
+            relay.force_state(boot_mask & (1 << relay.id) != 0)?;
+            log::info!("Relay {} ({}) restored to {}", relay.id, relay.name, relay.is_high());
+        }
+
+        Arc::new(Mutex::new(RelaySet::new(relays))) as Relays
     };
+    let relay_store = Arc::new(Mutex::new(relay_store));
+    // Mirrors the mask last written to NVS, so the debounced main-loop
+    // commit skips a mask that an immediate web toggle already wrote.
+    let committed_mask = Arc::new(Mutex::new(relays.lock().unwrap().mask()));
+
+    log::debug!("Initializing SNTP");
+    let _sntp = EspSntp::new_default()?;
+
+    log::debug!("Initializing schedules");
+    let schedule_store = Arc::new(Mutex::new(ScheduleStore::new(nvs)?));
+    let scheduler = Arc::new(Mutex::new(Scheduler::new(
+        schedule_store.lock().unwrap().load(),
+    )));
+
+    log::debug!("Initializing MQTT");
+    let mqtt = Arc::new(Mutex::new(Mqtt::new(MQTT_BROKER_URL, relays.clone())?));
 
     log::debug!("Initializing server");
     let mut server = create_server()?;
     server.fn_handler::<Error, _>("/", esp_idf_svc::http::Method::Get, {
         let relays = relays.clone();
+        let scheduler = scheduler.clone();
 
         move |req| {
             let relay_data = {
                 let relay_guard = relays.lock().unwrap();
-                let j = json!([
-                    {
-                        "name": "lampu kamar",
-                        "isActive": relay_guard.get(0).unwrap().1.is_high(),
-                        "id": 0,
-                    },
-                    {
-                        "name": "lampu keluarga",
-                        "isActive": relay_guard.get(1).unwrap().1.is_high(),
-                        "id": 1,
-                    },
-                    {
-                        "name": "lampu ruang tamu",
-                        "isActive": relay_guard.get(2).unwrap().1.is_high(),
-                        "id": 2,
-                    },
-                    {
-                        "name": "lampu dapur",
-                        "isActive": relay_guard.get(3).unwrap().1.is_high(),
-                        "id": 3,
+                let mut relay_json = relay_guard.to_json();
+                let next_actions = scheduler.lock().unwrap().next_actions_json(schedule::now());
+                if let Value::Array(relays) = &mut relay_json {
+                    for relay in relays.iter_mut() {
+                        if let Some(id) = relay.get("id").and_then(Value::as_u64) {
+                            if let Some(next_action) = next_actions.get(id.to_string()) {
+                                relay["nextAction"] = next_action.clone();
+                            }
+                        }
                     }
-                ]);
-                j.to_string()
+                }
+                relay_json.to_string()
             };
             let html = include_str!("../static/index.html").replace("$RELAYS", &relay_data);
             req.into_ok_response()?.write_all(html.as_bytes())?;
@@ -96,6 +150,9 @@ fn main() -> Result<()> {
 
     server.fn_handler::<Error, _>("/relay/toggle", esp_idf_svc::http::Method::Post, {
         let relays = relays.clone();
+        let mqtt = mqtt.clone();
+        let relay_store = relay_store.clone();
+        let committed_mask = committed_mask.clone();
         move |req| {
             log::info!("Relay parse req uri: {}", req.uri());
             let u = Url::parse(format!("http:///{}", req.uri()).as_str())?;
@@ -120,21 +177,25 @@ fn main() -> Result<()> {
             };
             log::info!("Relay parse req uri: {}", is_active);
 
-            if let Ok(mut relay_guard) = relays.lock() {
+            let (affected, mask) = {
+                let mut relay_guard = relays.lock().unwrap();
                 log::info!("Relay toggled via web");
-
-                if is_active {
-                    relay_guard
-                        .get_mut(relay_id as usize)
-                        .unwrap()
-                        .1
-                        .set_high()?;
+                let affected = relay_guard.set_state(relay_id as usize, is_active)?;
+                (affected, relay_guard.mask())
+            };
+            if let Ok(mut mqtt_guard) = mqtt.lock() {
+                for affected_id in &affected {
+                    let is_active = mask & (1 << affected_id) != 0;
+                    if let Err(e) = mqtt_guard.publish_state(*affected_id, is_active) {
+                        log::error!("Failed to publish relay {affected_id} state via MQTT: {e}");
+                    }
+                }
+            }
+            if let Ok(mut store_guard) = relay_store.lock() {
+                if let Err(e) = store_guard.save_mask(mask) {
+                    log::error!("Failed to persist relay mask: {e}");
                 } else {
-                    relay_guard
-                        .get_mut(relay_id as usize)
-                        .unwrap()
-                        .1
-                        .set_low()?;
+                    *committed_mask.lock().unwrap() = mask;
                 }
             }
             req.into_ok_response()?;
@@ -142,52 +203,207 @@ fn main() -> Result<()> {
         }
     })?;
 
-    log::debug!("Initializing button");
-    // TODO: make proper data structure for buttons
-    let mut btn1 = PinDriver::input(peripherals.pins.gpio15)?;
-    btn1.set_pull(Pull::Up)?;
-    btn1.set_interrupt_type(InterruptType::LowLevel)?;
-    let is_low1 = AtomicBool::new(false);
+    server.fn_handler::<Error, _>("/relay/boot-policy", esp_idf_svc::http::Method::Post, {
+        let relay_store = relay_store.clone();
+        move |req| {
+            let u = Url::parse(format!("http:///{}", req.uri()).as_str())?;
+            let policy = u
+                .query_pairs()
+                .find(|(k, _v)| k == "policy")
+                .and_then(|(_, v)| BootPolicy::parse(&v));
 
-    let mut btn2 = PinDriver::input(peripherals.pins.gpio18)?;
-    btn2.set_pull(Pull::Up)?;
-    btn2.set_interrupt_type(InterruptType::LowLevel)?;
-    let is_low2 = AtomicBool::new(false);
+            let Some(policy) = policy else {
+                req.into_response(400, Some("Unknown boot policy"), &[])?;
+                return Ok(());
+            };
 
-    let mut btn3 = PinDriver::input(peripherals.pins.gpio19)?;
-    btn3.set_pull(Pull::Up)?;
-    btn3.set_interrupt_type(InterruptType::LowLevel)?;
-    let is_low3 = AtomicBool::new(false);
+            if let Ok(mut store_guard) = relay_store.lock() {
+                store_guard.save_boot_policy(policy)?;
+            }
+            req.into_ok_response()?;
+            Ok(())
+        }
+    })?;
 
-    let mut btn4 = PinDriver::input(peripherals.pins.gpio21)?;
-    btn4.set_pull(Pull::Up)?;
-    btn4.set_interrupt_type(InterruptType::LowLevel)?;
-    let is_low4 = AtomicBool::new(false);
+    server.fn_handler::<Error, _>("/relay/config", esp_idf_svc::http::Method::Post, {
+        let relays = relays.clone();
+        move |req| {
+            let u = Url::parse(format!("http:///{}", req.uri()).as_str())?;
+            let query: Vec<(String, String)> = u
+                .query_pairs()
+                .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                .collect();
+            let find = |key: &str| query.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
 
-    log::info!("Starting main loop");
-    loop {
-        if btn1.is_low() && !is_low1.load(Ordering::Relaxed) {
-            if let Ok(mut relay_guard) = relays.lock() {
-                relay_guard.get_mut(0).unwrap().1.toggle()?;
-                log::info!("Relay toggled via button");
+            let Some(relay_id) = find("relayId").and_then(|v| v.parse::<usize>().ok()) else {
+                req.into_response(400, Some("Missing or invalid relayId"), &[])?;
+                return Ok(());
+            };
+            let Some(mode_kind) = find("mode") else {
+                req.into_response(400, Some("Missing mode"), &[])?;
+                return Ok(());
+            };
+            let pulse_ms = find("pulseMs").and_then(|v| v.parse::<u32>().ok());
+            let Some(mode) = RelayMode::parse(mode_kind, pulse_ms) else {
+                req.into_response(400, Some("Unknown relay mode"), &[])?;
+                return Ok(());
+            };
+            let interlock_group = find("interlockGroup").and_then(|v| v.parse::<u8>().ok());
+
+            let mut relay_guard = relays.lock().unwrap();
+            let Some(relay) = relay_guard.get_mut(relay_id) else {
+                req.into_response(400, Some("Relay ID is out of range"), &[])?;
+                return Ok(());
+            };
+            relay.mode = mode;
+            relay.interlock_group = interlock_group;
+
+            req.into_ok_response()?;
+            Ok(())
+        }
+    })?;
+
+    server.fn_handler::<Error, _>("/schedule", esp_idf_svc::http::Method::Get, {
+        let scheduler = scheduler.clone();
+        move |req| {
+            let body = Value::Array(
+                scheduler
+                    .lock()
+                    .unwrap()
+                    .entries()
+                    .iter()
+                    .map(ScheduleEntry::to_json)
+                    .collect(),
+            )
+            .to_string();
+            req.into_ok_response()?.write_all(body.as_bytes())?;
+            Ok(())
+        }
+    })?;
+
+    server.fn_handler::<Error, _>("/schedule", esp_idf_svc::http::Method::Post, {
+        let relays = relays.clone();
+        let scheduler = scheduler.clone();
+        let schedule_store = schedule_store.clone();
+        move |mut req| {
+            let mut body = Vec::new();
+            let mut buf = [0_u8; 256];
+            loop {
+                let read = req.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                body.extend_from_slice(&buf[..read]);
             }
+
+            let entry: ScheduleEntry = match serde_json::from_slice(&body) {
+                Ok(entry) => entry,
+                Err(_) => {
+                    req.into_response(400, Some("Invalid schedule entry"), &[])?;
+                    return Ok(());
+                }
+            };
+            let relay_count = relays.lock().unwrap().len();
+            if !entry.is_valid(relay_count) {
+                req.into_response(400, Some("Schedule entry out of range"), &[])?;
+                return Ok(());
+            }
+
+            let created = {
+                let mut scheduler_guard = scheduler.lock().unwrap();
+                let id = scheduler_guard.add(entry);
+                let mut store_guard = schedule_store.lock().unwrap();
+                store_guard.save(scheduler_guard.entries())?;
+                id
+            };
+
+            req.into_ok_response()?
+                .write_all(json!({ "id": created }).to_string().as_bytes())?;
+            Ok(())
         }
-        if btn2.is_low() && !is_low2.load(Ordering::Relaxed) {
-            if let Ok(mut relay_guard) = relays.lock() {
-                relay_guard.get_mut(1).unwrap().1.toggle()?;
-                log::info!("Relay toggled via button");
+    })?;
+
+    server.fn_handler::<Error, _>("/schedule", esp_idf_svc::http::Method::Delete, {
+        let scheduler = scheduler.clone();
+        let schedule_store = schedule_store.clone();
+        move |req| {
+            let u = Url::parse(format!("http:///{}", req.uri()).as_str())?;
+            let id = u
+                .query_pairs()
+                .find(|(k, _v)| k == "id")
+                .and_then(|(_, v)| v.parse::<u32>().ok());
+
+            let Some(id) = id else {
+                req.into_response(400, Some("Missing or invalid id"), &[])?;
+                return Ok(());
+            };
+
+            let mut scheduler_guard = scheduler.lock().unwrap();
+            if !scheduler_guard.remove(id) {
+                req.into_response(404, Some("Schedule entry not found"), &[])?;
+                return Ok(());
             }
+            let mut store_guard = schedule_store.lock().unwrap();
+            store_guard.save(scheduler_guard.entries())?;
+
+            req.into_ok_response()?;
+            Ok(())
         }
-        if btn3.is_low() && !is_low3.load(Ordering::Relaxed) {
-            if let Ok(mut relay_guard) = relays.lock() {
-                relay_guard.get_mut(2).unwrap().1.toggle()?;
-                log::info!("Relay toggled via button");
+    })?;
+
+    log::debug!("Initializing buttons");
+    let mut buttons = vec![
+        Button::new(peripherals.pins.gpio15.into(), 0)?,
+        Button::new(peripherals.pins.gpio18.into(), 1)?,
+        Button::new(peripherals.pins.gpio19.into(), 2)?,
+        Button::new(peripherals.pins.gpio21.into(), 3)?,
+    ];
+
+    let mut pending_mask = relays.lock().unwrap().mask();
+    let mut pending_ticks = 0_u32;
+    let mut last_schedule_minute: Option<(u8, u8, u8)> = None;
+
+    log::info!("Starting main loop");
+    loop {
+        for button in buttons.iter_mut() {
+            match button.tick(LOOP_TICK_MS) {
+                Some(Gesture::Short) => trigger_relay_via_button(&relays, &mqtt, button.relay_id),
+                Some(Gesture::Long) => {
+                    log::info!("Long press on relay {} button: all relays off", button.relay_id);
+                    set_all_relays(&relays, &mqtt, false);
+                }
+                Some(Gesture::Double) => {
+                    log::info!("Double press on relay {} button: all relays on", button.relay_id);
+                    set_all_relays(&relays, &mqtt, true);
+                }
+                None => {}
             }
         }
-        if btn4.is_low() && !is_low4.load(Ordering::Relaxed) {
-            if let Ok(mut relay_guard) = relays.lock() {
-                relay_guard.get_mut(3).unwrap().1.toggle()?;
-                log::info!("Relay toggled via button");
+
+        for id in relays.lock().unwrap().tick_momentary(LOOP_TICK_MS)? {
+            let is_active = relays.lock().unwrap().get(id).unwrap().is_high();
+            log::info!("Relay {id} auto-released after momentary pulse");
+            publish_relay_state(&mqtt, id, is_active);
+        }
+
+        let now = schedule::now();
+        let minute_marker = (now.weekday, now.hour, now.minute);
+        if last_schedule_minute != Some(minute_marker) {
+            last_schedule_minute = Some(minute_marker);
+            let driven = {
+                let mut relay_guard = relays.lock().unwrap();
+                let scheduler_guard = scheduler.lock().unwrap();
+                scheduler_guard.evaluate(&mut relay_guard, now)
+            };
+            match driven {
+                Ok(driven) => {
+                    for id in driven {
+                        let is_active = relays.lock().unwrap().get(id).unwrap().is_high();
+                        log::info!("Relay {id} driven by schedule");
+                        publish_relay_state(&mqtt, id, is_active);
+                    }
+                }
+                Err(e) => log::error!("Schedule evaluation failed: {e}"),
             }
         }
 
@@ -195,7 +411,7 @@ fn main() -> Result<()> {
             let mut i = 1;
             loop {
                 i += 1;
-                if let Err(e) = connect_wifi(&mut wifi) {
+                if let Err(e) = connect_wifi(&mut wifi, &wifi_creds.ssid, &wifi_creds.password) {
                     log::error!("Failed to connect to wifi: {}", e);
                 } else {
                     break;
@@ -207,20 +423,86 @@ fn main() -> Result<()> {
                 FreeRtos::delay_ms(delay);
             }
         }
-        is_low1.store(btn1.is_low(), Ordering::Relaxed);
-        is_low2.store(btn2.is_low(), Ordering::Relaxed);
-        is_low3.store(btn3.is_low(), Ordering::Relaxed);
-        is_low4.store(btn4.is_low(), Ordering::Relaxed);
-        FreeRtos::delay_ms(10);
+        let current_mask = relays.lock().unwrap().mask();
+        if current_mask == pending_mask {
+            pending_ticks += 1;
+        } else {
+            pending_mask = current_mask;
+            pending_ticks = 0;
+        }
+        if pending_mask != *committed_mask.lock().unwrap() && pending_ticks >= RELAY_MASK_DEBOUNCE_TICKS {
+            if let Ok(mut store_guard) = relay_store.lock() {
+                if let Err(e) = store_guard.save_mask(pending_mask) {
+                    log::error!("Failed to persist relay mask: {e}");
+                } else {
+                    *committed_mask.lock().unwrap() = pending_mask;
+                }
+            }
+        }
+
+        FreeRtos::delay_ms(LOOP_TICK_MS);
+    }
+}
+
+/// Applies a single button-press activation to relay `id` and republishes
+/// the resulting state of every relay it drove (itself, plus any sibling an
+/// interlock forced off) over MQTT.
+fn trigger_relay_via_button(relays: &Relays, mqtt: &Arc<Mutex<Mqtt>>, id: usize) {
+    let (affected, mask) = {
+        let mut relay_guard = relays.lock().unwrap();
+        let affected = match relay_guard.trigger(id) {
+            Ok(affected) => affected,
+            Err(e) => {
+                log::error!("Failed to trigger relay {id} via button: {e}");
+                return;
+            }
+        };
+        log::info!("Relay {id} toggled via button");
+        (affected, relay_guard.mask())
+    };
+    for affected_id in affected {
+        publish_relay_state(mqtt, affected_id, mask & (1 << affected_id) != 0);
+    }
+}
+
+/// Forces every relay to `is_active`, used for the all-off/all-on gestures,
+/// republishing each relay's resulting state over MQTT.
+fn set_all_relays(relays: &Relays, mqtt: &Arc<Mutex<Mqtt>>, is_active: bool) {
+    let count = relays.lock().unwrap().len();
+    for id in 0..count {
+        let (affected, mask) = {
+            let mut relay_guard = relays.lock().unwrap();
+            let affected = match relay_guard.set_state(id, is_active) {
+                Ok(affected) => affected,
+                Err(e) => {
+                    log::error!("Failed to set relay {id} from button gesture: {e}");
+                    continue;
+                }
+            };
+            (affected, relay_guard.mask())
+        };
+        for affected_id in affected {
+            publish_relay_state(mqtt, affected_id, mask & (1 << affected_id) != 0);
+        }
+    }
+}
+
+fn publish_relay_state(mqtt: &Arc<Mutex<Mqtt>>, id: usize, is_active: bool) {
+    if let Ok(mut mqtt_guard) = mqtt.lock() {
+        if let Err(e) = mqtt_guard.publish_state(id, is_active) {
+            log::error!("Failed to publish relay {id} state via MQTT: {e}");
+        }
     }
 }
 
-fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>) -> Result<()> {
+fn connect_wifi(wifi: &mut BlockingWifi<EspWifi<'static>>, ssid: &str, password: &str) -> Result<()> {
     let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-        ssid: SSID.try_into().unwrap(),
+        ssid: ssid.try_into().map_err(|_| anyhow::anyhow!("SSID too long"))?,
         bssid: None,
         auth_method: AuthMethod::WPA2Personal,
-        password: PASSWORD.try_into().unwrap(),
+        password: password
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("password too long"))?,
         channel: None,
         ..Default::default()
     });
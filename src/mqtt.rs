@@ -0,0 +1,145 @@
+//! MQTT bridge for remote control and telemetry.
+//!
+//! Mirrors the relay state that the HTTP server already exposes, but over a
+//! broker so the lamp can be wired into home-automation setups (Home
+//! Assistant, Node-RED, ...) instead of only being reachable through its own
+//! web page.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::Result;
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EspMqttConnection, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+
+use crate::Relays;
+
+const CLIENT_ID: &str = "lamp";
+const AVAILABILITY_TOPIC: &str = "lamp/availability";
+const ONLINE_PAYLOAD: &[u8] = br#"{"status":"online"}"#;
+const OFFLINE_PAYLOAD: &[u8] = br#"{"status":"offline"}"#;
+
+/// Handle to the background MQTT connection.
+///
+/// Publishing happens through [`Mqtt::publish_state`], or from the bridge
+/// thread itself when an incoming `lamp/<id>/set` command changes a relay.
+pub struct Mqtt {
+    client: Arc<Mutex<EspMqttClient<'static>>>,
+}
+
+impl Mqtt {
+    /// Connects to `broker_url` and starts bridging `relays` to MQTT.
+    ///
+    /// An MQTT last-will is registered on `lamp/availability` so the broker
+    /// marks the lamp offline if the connection drops uncleanly.
+    pub fn new(broker_url: &str, relays: Relays) -> Result<Self> {
+        let (client, mut connection) = EspMqttClient::new(
+            broker_url,
+            &MqttClientConfiguration {
+                client_id: Some(CLIENT_ID),
+                lwt: Some(LwtConfiguration {
+                    topic: AVAILABILITY_TOPIC,
+                    payload: OFFLINE_PAYLOAD,
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                }),
+                ..Default::default()
+            },
+        )?;
+        let client = Arc::new(Mutex::new(client));
+
+        let bridge_client = client.clone();
+        let bridge_relays = relays.clone();
+        thread::Builder::new()
+            .name("mqtt-bridge".into())
+            .stack_size(4096)
+            .spawn(move || run_bridge(&mut connection, &bridge_client, &bridge_relays))?;
+
+        let relay_count = relays.lock().unwrap().len();
+        let mut client_guard = client.lock().unwrap();
+        for id in 0..relay_count {
+            client_guard.subscribe(&format!("lamp/{id}/set"), QoS::AtLeastOnce)?;
+        }
+        client_guard.publish(AVAILABILITY_TOPIC, QoS::AtLeastOnce, true, ONLINE_PAYLOAD)?;
+        drop(client_guard);
+
+        Ok(Self { client })
+    }
+
+    /// Publishes the retained state of relay `id` to `lamp/<id>/state`.
+    ///
+    /// Called whenever a relay changes from the web UI, a button, a schedule
+    /// or a momentary release; changes commanded over MQTT are published by
+    /// the bridge thread itself.
+    pub fn publish_state(&mut self, id: usize, is_active: bool) -> Result<()> {
+        publish_state(&self.client, id, is_active)
+    }
+}
+
+fn publish_state(client: &Arc<Mutex<EspMqttClient<'static>>>, id: usize, is_active: bool) -> Result<()> {
+    let payload: &[u8] = if is_active { b"1" } else { b"0" };
+    client
+        .lock()
+        .unwrap()
+        .publish(&format!("lamp/{id}/state"), QoS::AtLeastOnce, true, payload)?;
+    Ok(())
+}
+
+fn run_bridge(connection: &mut EspMqttConnection, client: &Arc<Mutex<EspMqttClient<'static>>>, relays: &Relays) {
+    while let Ok(event) = connection.next() {
+        let EventPayload::Received {
+            topic: Some(topic),
+            data,
+            ..
+        } = event.payload()
+        else {
+            continue;
+        };
+
+        let Some(id) = parse_relay_id(topic) else {
+            continue;
+        };
+
+        let command = match std::str::from_utf8(data) {
+            Ok(s) => s.trim(),
+            Err(_) => continue,
+        };
+
+        let mut relay_guard = relays.lock().unwrap();
+        let result = match command {
+            "1" => relay_guard.set_state(id, true),
+            "0" => relay_guard.set_state(id, false),
+            "toggle" => relay_guard.trigger(id),
+            _ => {
+                log::warn!("Unknown MQTT command {command:?} for relay {id}");
+                continue;
+            }
+        };
+        let mask = relay_guard.mask();
+        drop(relay_guard);
+
+        let affected = match result {
+            Ok(affected) => affected,
+            Err(e) => {
+                log::error!("Failed to drive relay {id} from MQTT: {e}");
+                continue;
+            }
+        };
+        log::info!("Relay {id} driven via MQTT: {command}");
+
+        for affected_id in affected {
+            let is_active = mask & (1 << affected_id) != 0;
+            if let Err(e) = publish_state(client, affected_id, is_active) {
+                log::error!("Failed to publish relay {affected_id} state via MQTT: {e}");
+            }
+        }
+    }
+}
+
+/// Extracts `<id>` out of a `lamp/<id>/set` topic.
+fn parse_relay_id(topic: &str) -> Option<usize> {
+    let rest = topic.strip_prefix("lamp/")?;
+    let id_str = rest.strip_suffix("/set")?;
+    id_str.parse().ok()
+}
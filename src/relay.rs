@@ -0,0 +1,287 @@
+//! Relay abstraction: drive modes and interlock groups.
+//!
+//! Replaces the old `Vec<(&str, PinDriver)>` with a proper `Relay`/`RelaySet`
+//! pair so the HTTP, MQTT, and button call sites share one place that knows
+//! about momentary pulses and interlocks, instead of reimplementing the
+//! set/toggle logic at every call site.
+
+use anyhow::Result;
+use esp_idf_hal::gpio::{AnyIOPin, InputOutput, PinDriver};
+use serde_json::{json, Value};
+
+/// How a relay responds to an "activate" signal (a button press or an MQTT
+/// `toggle`). Explicit on/off commands (`/relay/toggle`, MQTT `1`/`0`,
+/// schedules) go through [`RelaySet::set_state`] instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayMode {
+    /// Flips between on and off on every activation (the original behaviour).
+    Toggle,
+    /// Drives the pin high for `pulse_ms`, then releases it automatically.
+    /// Useful for gate/door openers and other pulse-driven loads.
+    Momentary { pulse_ms: u32 },
+    /// An activation signal always turns the relay on; it only turns off in
+    /// response to an explicit off command (web/MQTT), never a toggle.
+    Latched,
+}
+
+impl RelayMode {
+    /// Parses the `/relay/config` spelling: `toggle`, `latched`, or
+    /// `momentary` (which additionally requires `pulse_ms`).
+    pub fn parse(kind: &str, pulse_ms: Option<u32>) -> Option<Self> {
+        match kind {
+            "toggle" => Some(RelayMode::Toggle),
+            "latched" => Some(RelayMode::Latched),
+            "momentary" => Some(RelayMode::Momentary {
+                pulse_ms: pulse_ms?,
+            }),
+            _ => None,
+        }
+    }
+
+    fn to_json(self) -> Value {
+        match self {
+            RelayMode::Toggle => json!({"type": "toggle"}),
+            RelayMode::Latched => json!({"type": "latched"}),
+            RelayMode::Momentary { pulse_ms } => {
+                json!({"type": "momentary", "pulseMs": pulse_ms})
+            }
+        }
+    }
+}
+
+/// A single relay output: the physical pin plus the behaviour it should
+/// have when activated.
+pub struct Relay {
+    pub id: usize,
+    pub name: &'static str,
+    pub mode: RelayMode,
+    pub interlock_group: Option<u8>,
+    pin: PinDriver<'static, AnyIOPin, InputOutput>,
+    pulse_remaining_ms: Option<u32>,
+}
+
+impl Relay {
+    pub fn new(id: usize, name: &'static str, pin: PinDriver<'static, AnyIOPin, InputOutput>) -> Self {
+        Self {
+            id,
+            name,
+            mode: RelayMode::Toggle,
+            interlock_group: None,
+            pin,
+            pulse_remaining_ms: None,
+        }
+    }
+
+    pub fn is_high(&self) -> bool {
+        self.pin.is_high()
+    }
+
+    /// Sets the pin directly, bypassing interlock bookkeeping. Only meant
+    /// for restoring boot state from NVS.
+    ///
+    /// Still arms the momentary-pulse timer when turning a `Momentary` relay
+    /// on. Relay modes are not stored in NVS: at boot every relay is still
+    /// `Toggle` when this runs, so a relay that was high before a power cut
+    /// comes back on without a pulse timer.
+    pub fn force_state(&mut self, is_active: bool) -> Result<()> {
+        if is_active {
+            self.pin.set_high()?;
+            if let RelayMode::Momentary { pulse_ms } = self.mode {
+                self.pulse_remaining_ms = Some(pulse_ms);
+            }
+        } else {
+            self.pin.set_low()?;
+            self.pulse_remaining_ms = None;
+        }
+        Ok(())
+    }
+
+    fn turn_on(&mut self) -> Result<()> {
+        self.pin.set_high()?;
+        if let RelayMode::Momentary { pulse_ms } = self.mode {
+            self.pulse_remaining_ms = Some(pulse_ms);
+        }
+        Ok(())
+    }
+
+    fn turn_off(&mut self) -> Result<()> {
+        self.pin.set_low()?;
+        self.pulse_remaining_ms = None;
+        Ok(())
+    }
+
+    /// Applies a single "activate" signal. Returns the resulting state.
+    fn trigger(&mut self) -> Result<Option<bool>> {
+        match self.mode {
+            RelayMode::Toggle => {
+                if self.is_high() {
+                    self.turn_off()?;
+                } else {
+                    self.turn_on()?;
+                }
+            }
+            RelayMode::Momentary { .. } | RelayMode::Latched => self.turn_on()?,
+        }
+        Ok(Some(self.is_high()))
+    }
+
+    /// Applies an explicit on/off command. Returns the resulting state, or
+    /// `None` if the command was a no-op (an explicit off on a momentary
+    /// relay, which only auto-releases).
+    fn set_state(&mut self, is_active: bool) -> Result<Option<bool>> {
+        if matches!(self.mode, RelayMode::Momentary { .. }) && !is_active {
+            log::warn!(
+                "Relay {} ({}) is momentary; ignoring explicit off",
+                self.id,
+                self.name
+            );
+            return Ok(None);
+        }
+        if is_active {
+            self.turn_on()?;
+        } else {
+            self.turn_off()?;
+        }
+        Ok(Some(is_active))
+    }
+
+    /// Advances momentary-pulse bookkeeping by `elapsed_ms`. Returns `true`
+    /// if the relay just auto-released.
+    fn tick(&mut self, elapsed_ms: u32) -> Result<bool> {
+        let Some(remaining) = self.pulse_remaining_ms else {
+            return Ok(false);
+        };
+        if remaining <= elapsed_ms {
+            self.turn_off()?;
+            Ok(true)
+        } else {
+            self.pulse_remaining_ms = Some(remaining - elapsed_ms);
+            Ok(false)
+        }
+    }
+
+    pub fn to_json(&self, is_active: bool) -> Value {
+        json!({
+            "id": self.id,
+            "name": self.name,
+            "isActive": is_active,
+            "mode": self.mode.to_json(),
+            "interlockGroup": self.interlock_group,
+        })
+    }
+}
+
+/// All relays on the device, with interlock enforcement applied whenever one
+/// turns on.
+pub struct RelaySet {
+    relays: Vec<Relay>,
+}
+
+impl RelaySet {
+    pub fn new(relays: Vec<Relay>) -> Self {
+        Self { relays }
+    }
+
+    pub fn len(&self) -> usize {
+        self.relays.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.relays.is_empty()
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Relay> {
+        self.relays.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Relay> {
+        self.relays.get_mut(id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Relay> {
+        self.relays.iter()
+    }
+
+    /// Activates relay `id` as a single button/MQTT-toggle press would.
+    /// Returns the ids of every relay it drove: `id` itself (even if it was
+    /// already in the resulting state) and any sibling forced off by an
+    /// interlock.
+    pub fn trigger(&mut self, id: usize) -> Result<Vec<usize>> {
+        self.apply(id, Relay::trigger)
+    }
+
+    /// Drives relay `id` to an explicit on/off state. Returns the ids of
+    /// every relay it drove: `id` itself (even if it was already in that
+    /// state; not for an ignored off on a momentary relay) and any sibling
+    /// forced off by an interlock.
+    pub fn set_state(&mut self, id: usize, is_active: bool) -> Result<Vec<usize>> {
+        self.apply(id, move |relay| relay.set_state(is_active))
+    }
+
+    fn apply(&mut self, id: usize, f: impl FnOnce(&mut Relay) -> Result<Option<bool>>) -> Result<Vec<usize>> {
+        let Some(relay) = self.relays.get_mut(id) else {
+            return Ok(Vec::new());
+        };
+        let Some(turned_on) = f(relay)? else {
+            return Ok(Vec::new());
+        };
+        let mut affected = vec![id];
+        if turned_on {
+            affected.extend(self.enforce_interlock(id)?);
+        }
+        Ok(affected)
+    }
+
+    /// Forces off every other relay sharing `id`'s interlock group.
+    /// Returns the ids of the relays that were actually forced off.
+    fn enforce_interlock(&mut self, id: usize) -> Result<Vec<usize>> {
+        let Some(group) = self.relays.get(id).and_then(|r| r.interlock_group) else {
+            return Ok(Vec::new());
+        };
+        let mut forced_off = Vec::new();
+        for other in self.relays.iter_mut() {
+            if other.id != id && other.interlock_group == Some(group) && other.is_high() {
+                log::info!(
+                    "Interlock group {group}: forcing relay {} ({}) off",
+                    other.id,
+                    other.name
+                );
+                other.turn_off()?;
+                forced_off.push(other.id);
+            }
+        }
+        Ok(forced_off)
+    }
+
+    /// Advances every relay's momentary-pulse bookkeeping by `elapsed_ms`.
+    /// Returns the ids of relays that just auto-released.
+    pub fn tick_momentary(&mut self, elapsed_ms: u32) -> Result<Vec<usize>> {
+        let mut released = Vec::new();
+        for relay in self.relays.iter_mut() {
+            if relay.tick(elapsed_ms)? {
+                released.push(relay.id);
+            }
+        }
+        Ok(released)
+    }
+
+    /// The current on/off state of every relay, packed one bit per id.
+    pub fn mask(&self) -> u32 {
+        self.relays.iter().fold(0_u32, |mask, relay| {
+            if relay.is_high() {
+                mask | (1 << relay.id)
+            } else {
+                mask
+            }
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::Array(
+            self.relays
+                .iter()
+                .map(|relay| relay.to_json(relay.is_high()))
+                .collect(),
+        )
+    }
+}
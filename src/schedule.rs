@@ -0,0 +1,182 @@
+//! Time-based relay schedules, evaluated against SNTP-synced wall-clock time.
+//!
+//! Each entry fires once, on every matching minute, forcing `relay_id` to
+//! `target_state`. Days-of-week are bit-packed (bit 0 = Sunday ... bit 6 =
+//! Saturday), so e.g. "weekdays only" is `0b0111110`.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::relay::RelaySet;
+
+/// Local UTC offset applied to SNTP-synced time before schedules are
+/// evaluated, in minutes. SNTP itself always reports UTC, but a schedule
+/// like "on at dusk" is inherently local wall-clock time.
+const UTC_OFFSET_MINUTES: i32 = 7 * 60;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ScheduleEntry {
+    #[serde(default)]
+    pub id: u32,
+    pub relay_id: usize,
+    pub days: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub target_state: bool,
+}
+
+impl ScheduleEntry {
+    /// Whether every field is in range: `relay_id` under `relay_count`,
+    /// `hour` 0-23, `minute` 0-59, and `days` only setting the weekday bits
+    /// (0-6, Sunday-Saturday).
+    pub fn is_valid(&self, relay_count: usize) -> bool {
+        self.relay_id < relay_count && self.hour < 24 && self.minute < 60 && self.days & !0b0111_1111 == 0
+    }
+
+    fn matches(&self, now: WallClock) -> bool {
+        self.days & (1 << now.weekday) != 0 && self.hour == now.hour && self.minute == now.minute
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "relayId": self.relay_id,
+            "days": self.days,
+            "hour": self.hour,
+            "minute": self.minute,
+            "targetState": self.target_state,
+        })
+    }
+}
+
+/// Current wall-clock time, decomposed the way [`ScheduleEntry`] needs it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WallClock {
+    /// 0 = Sunday ... 6 = Saturday.
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+}
+
+/// Reads the current local wall-clock time, i.e. SNTP-synced UTC shifted by
+/// [`UTC_OFFSET_MINUTES`]. Meaningless until SNTP has completed its first
+/// sync.
+pub fn now() -> WallClock {
+    let utc_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let secs = (utc_secs + UTC_OFFSET_MINUTES as i64 * 60).max(0) as u64;
+    let days = secs / 86_400;
+    let time_of_day = secs % 86_400;
+    // 1970-01-01 was a Thursday (weekday index 4 with Sunday = 0).
+    let weekday = ((days + 4) % 7) as u8;
+    WallClock {
+        weekday,
+        hour: (time_of_day / 3600) as u8,
+        minute: ((time_of_day % 3600) / 60) as u8,
+    }
+}
+
+/// The persisted schedule list, plus evaluation against wall-clock time.
+pub struct Scheduler {
+    entries: Vec<ScheduleEntry>,
+    next_id: u32,
+}
+
+impl Scheduler {
+    pub fn new(entries: Vec<ScheduleEntry>) -> Self {
+        let next_id = entries.iter().map(|e| e.id).max().map_or(0, |max| max + 1);
+        Self { entries, next_id }
+    }
+
+    pub fn entries(&self) -> &[ScheduleEntry] {
+        &self.entries
+    }
+
+    /// Adds `entry`, assigning it a fresh id, and returns that id.
+    pub fn add(&mut self, mut entry: ScheduleEntry) -> u32 {
+        entry.id = self.next_id;
+        self.next_id += 1;
+        let id = entry.id;
+        self.entries.push(entry);
+        id
+    }
+
+    /// Removes the entry with `id`. Returns whether one was found.
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len_before = self.entries.len();
+        self.entries.retain(|entry| entry.id != id);
+        self.entries.len() != len_before
+    }
+
+    /// Applies every entry matching `now`, returning the ids of relays it
+    /// drove (the commanded relay, plus any sibling an interlock forced
+    /// off).
+    pub fn evaluate(&self, relays: &mut RelaySet, now: WallClock) -> Result<Vec<usize>> {
+        let mut driven = Vec::new();
+        for entry in &self.entries {
+            if entry.matches(now) {
+                driven.extend(relays.set_state(entry.relay_id, entry.target_state)?);
+            }
+        }
+        Ok(driven)
+    }
+
+    /// The next upcoming action per relay, as `{"<relayId>": {"targetState":
+    /// bool, "minutesUntil": number}}`, for embedding in the `/` page.
+    pub fn next_actions_json(&self, now: WallClock) -> Value {
+        let mut by_relay: BTreeMap<usize, (ScheduleEntry, u32)> = BTreeMap::new();
+        for entry in &self.entries {
+            let Some(minutes_until) = minutes_until(now, entry) else {
+                continue;
+            };
+            by_relay
+                .entry(entry.relay_id)
+                .and_modify(|(best_entry, best_minutes)| {
+                    if minutes_until < *best_minutes {
+                        *best_entry = *entry;
+                        *best_minutes = minutes_until;
+                    }
+                })
+                .or_insert((*entry, minutes_until));
+        }
+        Value::Object(
+            by_relay
+                .into_iter()
+                .map(|(relay_id, (entry, minutes_until))| {
+                    (
+                        relay_id.to_string(),
+                        json!({
+                            "targetState": entry.target_state,
+                            "minutesUntil": minutes_until,
+                        }),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Minutes from `now` until `entry` next fires, searching up to a week
+/// ahead (inclusive, so a once-weekly entry whose time already passed
+/// today still matches on the same weekday next week). `None` if
+/// `entry.days` never matches (an empty mask).
+fn minutes_until(now: WallClock, entry: &ScheduleEntry) -> Option<u32> {
+    let now_minutes = now.hour as i32 * 60 + now.minute as i32;
+    for day_offset in 0..=7_i32 {
+        let weekday = (now.weekday as i32 + day_offset).rem_euclid(7) as u8;
+        if entry.days & (1 << weekday) == 0 {
+            continue;
+        }
+        let target_minutes = entry.hour as i32 * 60 + entry.minute as i32;
+        let candidate = day_offset * 1440 + target_minutes - now_minutes;
+        if candidate > 0 {
+            return Some(candidate as u32);
+        }
+    }
+    None
+}
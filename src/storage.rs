@@ -0,0 +1,180 @@
+//! NVS-backed persistence: relay state/boot behaviour and WiFi credentials.
+//!
+//! Relay states are packed into a single `u32` bitmask (one bit per relay,
+//! matching the `id` used throughout the JSON/HTTP API) so the whole set can
+//! be read or written in one NVS access.
+
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+
+use crate::schedule::ScheduleEntry;
+
+const NAMESPACE: &str = "lamp";
+const MASK_KEY: &str = "relay_mask";
+const BOOT_POLICY_KEY: &str = "boot_policy";
+
+const WIFI_NAMESPACE: &str = "lamp_wifi";
+const SSID_KEY: &str = "ssid";
+const PASSWORD_KEY: &str = "password";
+const WIFI_STR_MAX_LEN: usize = 64;
+
+const SCHEDULE_NAMESPACE: &str = "lamp_sched";
+const SCHEDULE_KEY: &str = "entries";
+/// Room for about two dozen schedule entries as serialized JSON.
+const SCHEDULE_BUF_LEN: usize = 2048;
+
+/// How relays should come up after a power outage or reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootPolicy {
+    /// Restore whatever state was last persisted to NVS.
+    RestoreLast,
+    /// Ignore the persisted mask and turn every relay on.
+    AlwaysOn,
+    /// Ignore the persisted mask and turn every relay off.
+    AlwaysOff,
+}
+
+impl BootPolicy {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => BootPolicy::AlwaysOn,
+            2 => BootPolicy::AlwaysOff,
+            _ => BootPolicy::RestoreLast,
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            BootPolicy::RestoreLast => 0,
+            BootPolicy::AlwaysOn => 1,
+            BootPolicy::AlwaysOff => 2,
+        }
+    }
+
+    /// Parses the query-param spelling used by the HTTP handlers
+    /// (`restore-last`, `always-on`, `always-off`).
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "restore-last" => Some(BootPolicy::RestoreLast),
+            "always-on" => Some(BootPolicy::AlwaysOn),
+            "always-off" => Some(BootPolicy::AlwaysOff),
+            _ => None,
+        }
+    }
+}
+
+/// Thin wrapper around the `lamp` NVS namespace used for relay state.
+pub struct RelayStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl RelayStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    /// Loads the persisted relay-state bitmask, defaulting to all-off if
+    /// nothing has been stored yet.
+    pub fn load_mask(&self) -> u32 {
+        self.nvs.get_u32(MASK_KEY).unwrap_or(None).unwrap_or(0)
+    }
+
+    /// Commits `mask` to NVS. Callers should debounce this themselves; every
+    /// call is a flash write.
+    pub fn save_mask(&mut self, mask: u32) -> Result<()> {
+        self.nvs.set_u32(MASK_KEY, mask)?;
+        Ok(())
+    }
+
+    pub fn load_boot_policy(&self) -> BootPolicy {
+        let raw = self.nvs.get_u8(BOOT_POLICY_KEY).unwrap_or(None).unwrap_or(0);
+        BootPolicy::from_u8(raw)
+    }
+
+    pub fn save_boot_policy(&mut self, policy: BootPolicy) -> Result<()> {
+        self.nvs.set_u8(BOOT_POLICY_KEY, policy.as_u8())?;
+        Ok(())
+    }
+}
+
+/// WiFi credentials accepted through Improv serial provisioning.
+#[derive(Debug, Clone)]
+pub struct WifiCredentials {
+    pub ssid: String,
+    pub password: String,
+}
+
+/// Thin wrapper around the `lamp_wifi` NVS namespace.
+///
+/// Separate from [`RelayStore`] so credentials and relay bookkeeping can be
+/// wiped independently (e.g. a factory reset that forgets WiFi but keeps
+/// relay state).
+pub struct WifiStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl WifiStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, WIFI_NAMESPACE, true)?,
+        })
+    }
+
+    /// Loads previously-provisioned credentials, if any have been saved.
+    pub fn load(&self) -> Option<WifiCredentials> {
+        let mut ssid_buf = [0_u8; WIFI_STR_MAX_LEN];
+        let mut password_buf = [0_u8; WIFI_STR_MAX_LEN];
+        let ssid = self.nvs.get_str(SSID_KEY, &mut ssid_buf).ok()??;
+        let password = self.nvs.get_str(PASSWORD_KEY, &mut password_buf).ok()??;
+        Some(WifiCredentials {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    pub fn save(&mut self, creds: &WifiCredentials) -> Result<()> {
+        self.nvs.set_str(SSID_KEY, &creds.ssid)?;
+        self.nvs.set_str(PASSWORD_KEY, &creds.password)?;
+        Ok(())
+    }
+}
+
+/// Thin wrapper around the `lamp_sched` NVS namespace used for schedule
+/// entries, stored as a single serialized-JSON blob.
+pub struct ScheduleStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl ScheduleStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self> {
+        Ok(Self {
+            nvs: EspNvs::new(partition, SCHEDULE_NAMESPACE, true)?,
+        })
+    }
+
+    pub fn load(&self) -> Vec<ScheduleEntry> {
+        let mut buf = vec![0_u8; SCHEDULE_BUF_LEN];
+        match self.nvs.get_raw(SCHEDULE_KEY, &mut buf) {
+            Ok(Some(bytes)) => serde_json::from_slice(bytes).unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn save(&mut self, entries: &[ScheduleEntry]) -> Result<()> {
+        let bytes = serde_json::to_vec(entries)?;
+        self.nvs.set_raw(SCHEDULE_KEY, &bytes)?;
+        Ok(())
+    }
+}
+
+/// Computes the persisted-state bitmask that `relays` should boot into,
+/// given `policy` and whatever was last saved.
+pub fn initial_mask(policy: BootPolicy, saved_mask: u32, relay_count: usize) -> u32 {
+    match policy {
+        BootPolicy::RestoreLast => saved_mask,
+        BootPolicy::AlwaysOn => (1u32 << relay_count) - 1,
+        BootPolicy::AlwaysOff => 0,
+    }
+}